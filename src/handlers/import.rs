@@ -1,5 +1,7 @@
 use axum::{extract::State, Json};
 use chrono::Utc;
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::sync::Arc;
 use uuid::Uuid;
 use worker::{query, Env, D1PreparedStatement};
@@ -10,7 +12,281 @@ use crate::db;
 use crate::error::AppError;
 use crate::models::cipher::{Cipher, CipherData};
 use crate::models::folder::Folder;
-use crate::models::import::ImportRequest;
+use crate::models::import::{ImportMode, ImportRequest};
+
+#[derive(Deserialize)]
+struct ExistingFolder {
+    id: String,
+    name: String,
+}
+
+/// Upper bound on combined folders + ciphers accepted in a single import
+/// request. This service's own guard against unbounded batches; not a
+/// value Vaultwarden enforces.
+const MAX_IMPORT_ITEMS: usize = 6000;
+/// Matches Vaultwarden's `validate_notes`.
+const MAX_NOTES_LENGTH: usize = 10000;
+/// This service's own cap; Vaultwarden does not limit fields per cipher.
+const MAX_FIELDS_PER_CIPHER: usize = 1000;
+
+#[derive(Deserialize)]
+struct MembershipPolicy {
+    use_totp: bool,
+}
+
+#[derive(Deserialize)]
+struct CollectionAccess {
+    organization_id: String,
+    has_access: bool,
+    read_only: bool,
+}
+
+fn check_cipher_organization_membership(
+    org_totp_policies: &HashMap<String, bool>,
+    index: usize,
+    organization_id: &str,
+) -> Result<(), AppError> {
+    if org_totp_policies.contains_key(organization_id) {
+        Ok(())
+    } else {
+        Err(AppError::BadRequest(format!(
+            "Cipher at index {} targets an organization the user does not belong to",
+            index
+        )))
+    }
+}
+
+/// Confirm `user_id` actually belongs to every organization referenced by
+/// the payload's ciphers, and return each referenced org's TOTP-enforcement
+/// policy along the way. Vaultwarden flags the membership check as a TODO;
+/// without it an import could silently attach a cipher to an org the
+/// importing user has no membership in.
+async fn verify_organization_membership(
+    db: &worker::D1Database,
+    user_id: &str,
+    payload: &ImportRequest,
+) -> Result<HashMap<String, bool>, AppError> {
+    let mut org_totp_policies: HashMap<String, bool> = HashMap::new();
+
+    for (index, import_cipher) in payload.ciphers.iter().enumerate() {
+        let Some(organization_id) = &import_cipher.organization_id else {
+            continue;
+        };
+        if org_totp_policies.contains_key(organization_id) {
+            continue;
+        }
+
+        let membership: Option<MembershipPolicy> = db
+            .prepare(
+                "SELECT o.use_totp AS use_totp FROM memberships m \
+                 JOIN organizations o ON o.id = m.organization_id \
+                 WHERE m.user_id = ?1 AND m.organization_id = ?2",
+            )
+            .bind(&[user_id.into(), organization_id.as_str().into()])?
+            .first(None)
+            .await
+            .map_err(|_| AppError::Database)?;
+
+        if let Some(policy) = membership {
+            org_totp_policies.insert(organization_id.clone(), policy.use_totp);
+        }
+
+        check_cipher_organization_membership(&org_totp_policies, index, organization_id)?;
+    }
+
+    Ok(org_totp_policies)
+}
+
+fn check_collection_assignment(
+    collections: &HashMap<String, CollectionAccess>,
+    cipher_index: usize,
+    cipher_organization_id: Option<&str>,
+    collection_id: &str,
+) -> Result<(), AppError> {
+    let Some(cipher_org) = cipher_organization_id else {
+        return Err(AppError::BadRequest(format!(
+            "Cipher at index {} has no organization to scope collection {} to",
+            cipher_index, collection_id
+        )));
+    };
+
+    let Some(access) = collections.get(collection_id) else {
+        return Err(AppError::BadRequest(format!(
+            "Cipher at index {} references a collection that does not exist",
+            cipher_index
+        )));
+    };
+
+    if access.organization_id != cipher_org {
+        return Err(AppError::BadRequest(format!(
+            "Cipher at index {} references collection {} outside its organization",
+            cipher_index, collection_id
+        )));
+    }
+
+    // Org membership alone isn't enough: Vaultwarden scopes collection
+    // access per-user (`CollectionUser`/`Collection::can_edit`) independent
+    // of org membership, so a member with no explicit grant — or a
+    // read-only grant — on this collection must still be rejected.
+    if !access.has_access || access.read_only {
+        return Err(AppError::BadRequest(format!(
+            "Cipher at index {} references collection {} the user cannot edit",
+            cipher_index, collection_id
+        )));
+    }
+
+    Ok(())
+}
+
+/// Confirm every `collection_relationships` entry points at a collection
+/// that belongs to the targeted cipher's own organization AND that
+/// `user_id` has a non-read-only grant on, so a user can't use import to
+/// attach a cipher to an arbitrary guessed/leaked collection id, or to one
+/// they're an org member but not a collection member of.
+async fn verify_collection_assignments(
+    db: &worker::D1Database,
+    user_id: &str,
+    payload: &ImportRequest,
+) -> Result<(), AppError> {
+    let mut collections: HashMap<String, CollectionAccess> = HashMap::new();
+
+    for relationship in &payload.collection_relationships {
+        if collections.contains_key(&relationship.value) {
+            continue;
+        }
+
+        let row: Option<CollectionAccess> = db
+            .prepare(
+                "SELECT c.organization_id AS organization_id, \
+                        (cu.user_id IS NOT NULL) AS has_access, \
+                        COALESCE(cu.read_only, 0) AS read_only \
+                 FROM collections c \
+                 LEFT JOIN collection_users cu ON cu.collection_id = c.id AND cu.user_id = ?1 \
+                 WHERE c.id = ?2",
+            )
+            .bind(&[user_id.into(), relationship.value.as_str().into()])?
+            .first(None)
+            .await
+            .map_err(|_| AppError::Database)?;
+
+        if let Some(row) = row {
+            collections.insert(relationship.value.clone(), row);
+        }
+    }
+
+    for relationship in &payload.collection_relationships {
+        let cipher_organization_id = payload
+            .ciphers
+            .get(relationship.key)
+            .and_then(|c| c.organization_id.as_deref());
+
+        check_collection_assignment(
+            &collections,
+            relationship.key,
+            cipher_organization_id,
+            &relationship.value,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Validate the whole payload up front so a bad entry is rejected before any
+/// statement runs, rather than after earlier batches have already committed.
+fn validate_import(payload: &ImportRequest, user_id: &str) -> Result<(), AppError> {
+    let total_items = payload.folders.len() + payload.ciphers.len();
+    if total_items > MAX_IMPORT_ITEMS {
+        return Err(AppError::BadRequest(format!(
+            "Import request exceeds the maximum of {} folders and ciphers",
+            MAX_IMPORT_ITEMS
+        )));
+    }
+
+    // collection_relationships each turn 1:1 into a statement in the same
+    // batch as the folders/ciphers above, and folder_relationships are
+    // walked entry by entry before that batch is built, so they need the
+    // same bound or a payload with few ciphers but huge relationship arrays
+    // could grow the work unchecked.
+    let total_relationships =
+        payload.folder_relationships.len() + payload.collection_relationships.len();
+    if total_relationships > MAX_IMPORT_ITEMS {
+        return Err(AppError::BadRequest(format!(
+            "Import request exceeds the maximum of {} folder and collection relationships",
+            MAX_IMPORT_ITEMS
+        )));
+    }
+
+    for (index, import_cipher) in payload.ciphers.iter().enumerate() {
+        if import_cipher.encrypted_for != user_id {
+            return Err(AppError::BadRequest(format!(
+                "Cipher at index {} is encrypted for the wrong user",
+                index
+            )));
+        }
+
+        if let Some(notes) = &import_cipher.notes {
+            if notes.len() > MAX_NOTES_LENGTH {
+                return Err(AppError::BadRequest(format!(
+                    "Cipher at index {} has notes longer than {} characters",
+                    index, MAX_NOTES_LENGTH
+                )));
+            }
+        }
+
+        if let Some(fields) = &import_cipher.fields {
+            if fields.len() > MAX_FIELDS_PER_CIPHER {
+                return Err(AppError::BadRequest(format!(
+                    "Cipher at index {} has more than {} custom fields",
+                    index, MAX_FIELDS_PER_CIPHER
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolve each imported folder to an id that's safe to insert: reuse the
+/// folder's own id if the user already owns it, fall back to an existing
+/// folder with the same name, and only mint a fresh id otherwise. Mirrors
+/// Vaultwarden's import behavior so re-importing an export doesn't spawn
+/// duplicate folders.
+async fn resolve_folder_ids(
+    db: &worker::D1Database,
+    user_id: &str,
+    payload_folders: &[crate::models::import::ImportFolder],
+) -> Result<Vec<String>, AppError> {
+    let existing: Vec<ExistingFolder> = db
+        .prepare("SELECT id, name FROM folders WHERE user_id = ?1")
+        .bind(&[user_id.into()])?
+        .all()
+        .await
+        .map_err(|_| AppError::Database)?
+        .results()
+        .map_err(|_| AppError::Database)?;
+
+    let existing_ids: HashMap<&str, &str> = existing
+        .iter()
+        .map(|f| (f.id.as_str(), f.name.as_str()))
+        .collect();
+    let existing_names: HashMap<&str, &str> = existing
+        .iter()
+        .map(|f| (f.name.as_str(), f.id.as_str()))
+        .collect();
+
+    Ok(payload_folders
+        .iter()
+        .map(|import_folder| {
+            if existing_ids.contains_key(import_folder.id.as_str()) {
+                import_folder.id.clone()
+            } else if let Some(id) = existing_names.get(import_folder.name.as_str()) {
+                id.to_string()
+            } else {
+                Uuid::new_v4().to_string()
+            }
+        })
+        .collect())
+}
 
 #[worker::send]
 pub async fn import_data(
@@ -18,23 +294,64 @@ pub async fn import_data(
     State(env): State<Arc<Env>>,
     Json(mut payload): Json<ImportRequest>,
 ) -> Result<Json<()>, AppError> {
+    validate_import(&payload, &claims.sub)?;
+
     let db = db::get_db(&env)?;
     let now = Utc::now();
     let now = now.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
 
-    let mut folder_stmts: Vec<D1PreparedStatement> = Vec::new();
+    let org_totp_policies = verify_organization_membership(&db, &claims.sub, &payload).await?;
+    verify_collection_assignments(&db, &claims.sub, &payload).await?;
+
+    let resolved_folder_ids = match payload.mode {
+        // The user's existing folders are about to be purged below, so
+        // there's nothing to dedupe against — every imported folder gets a
+        // fresh id.
+        ImportMode::Replace => payload
+            .folders
+            .iter()
+            .map(|_| Uuid::new_v4().to_string())
+            .collect(),
+        ImportMode::Append => resolve_folder_ids(&db, &claims.sub, &payload.folders).await?,
+    };
+
+    let mut stmts: Vec<D1PreparedStatement> = Vec::new();
+
+    if payload.mode == ImportMode::Replace {
+        stmts.push(
+            db.prepare(
+                "UPDATE ciphers SET deleted_at = ?1 WHERE user_id = ?2 AND organization_id IS NULL AND deleted_at IS NULL",
+            )
+            .bind(&[now.clone().into(), claims.sub.clone().into()])?,
+        );
+        // Every one of the user's ciphers that still has a folder_id needs
+        // clearing before the DELETE below, not just the org-owned ones:
+        // the personal ciphers soft-deleted above keep their folder_id too,
+        // and it would otherwise dangle once the folder row is gone.
+        stmts.push(
+            db.prepare(
+                "UPDATE ciphers SET folder_id = NULL WHERE user_id = ?1 AND folder_id IS NOT NULL",
+            )
+            .bind(&[claims.sub.clone().into()])?,
+        );
+        stmts.push(
+            db.prepare("DELETE FROM folders WHERE user_id = ?1")
+                .bind(&[claims.sub.clone().into()])?,
+        );
+    }
+
     let folder_query = "INSERT OR IGNORE INTO folders (id, user_id, name, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5)";
 
-    for import_folder in &payload.folders {
+    for (import_folder, folder_id) in payload.folders.iter().zip(resolved_folder_ids.iter()) {
         let folder = Folder {
-            id: import_folder.id.clone(),
+            id: folder_id.clone(),
             user_id: claims.sub.clone(),
             name: import_folder.name.clone(),
             created_at: now.clone(),
             updated_at: now.clone(),
         };
 
-        folder_stmts.push(db.prepare(folder_query).bind(&[
+        stmts.push(db.prepare(folder_query).bind(&[
             folder.id.into(),
             folder.user_id.into(),
             folder.name.into(),
@@ -42,26 +359,29 @@ pub async fn import_data(
             folder.updated_at.into(),
         ])?);
     }
-    
-    if !folder_stmts.is_empty() {
-        db.batch(folder_stmts).await.map_err(|_| AppError::Database)?;
-    }
 
     for relationship in payload.folder_relationships {
         if let Some(cipher) = payload.ciphers.get_mut(relationship.key) {
-            if let Some(folder) = payload.folders.get(relationship.value) {
-                cipher.folder_id = Some(folder.id.clone());
+            if let Some(folder_id) = resolved_folder_ids.get(relationship.value) {
+                cipher.folder_id = Some(folder_id.clone());
             }
         }
     }
 
-    let mut cipher_stmts: Vec<D1PreparedStatement> = Vec::new();
+    let mut collections_by_cipher: HashMap<usize, Vec<String>> = HashMap::new();
+    for relationship in payload.collection_relationships {
+        collections_by_cipher
+            .entry(relationship.key)
+            .or_default()
+            .push(relationship.value);
+    }
+
     let cipher_query = "INSERT OR IGNORE INTO ciphers (id, user_id, organization_id, type, data, favorite, folder_id, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)";
+    let collection_query =
+        "INSERT OR IGNORE INTO ciphers_collections (cipher_id, collection_id) VALUES (?1, ?2)";
 
-    for import_cipher in payload.ciphers {
-        if import_cipher.encrypted_for != claims.sub {
-            return Err(AppError::BadRequest("Cipher encrypted for wrong user".to_string()));
-        }
+    for (index, import_cipher) in payload.ciphers.into_iter().enumerate() {
+        let collection_ids = collections_by_cipher.remove(&index);
 
         let cipher_data = CipherData {
             name: import_cipher.name,
@@ -77,6 +397,13 @@ pub async fn import_data(
 
         let data_value = serde_json::to_value(&cipher_data).map_err(|_| AppError::Internal)?;
 
+        let organization_use_totp = import_cipher
+            .organization_id
+            .as_deref()
+            .and_then(|org_id| org_totp_policies.get(org_id))
+            .copied()
+            .unwrap_or(false);
+
         let cipher = Cipher {
             id: Uuid::new_v4().to_string(),
             user_id: Some(claims.sub.clone()),
@@ -89,16 +416,16 @@ pub async fn import_data(
             created_at: now.clone(),
             updated_at: now.clone(),
             object: "cipher".to_string(),
-            organization_use_totp: false,
+            organization_use_totp,
             edit: true,
             view_password: true,
-            collection_ids: None,
+            collection_ids: collection_ids.clone(),
         };
 
         let data = serde_json::to_string(&cipher.data).map_err(|_| AppError::Internal)?;
 
-        cipher_stmts.push(db.prepare(cipher_query).bind(&[
-            cipher.id.into(),
+        stmts.push(db.prepare(cipher_query).bind(&[
+            cipher.id.clone().into(),
             to_js_val(cipher.user_id),
             to_js_val(cipher.organization_id),
             cipher.r#type.into(),
@@ -108,10 +435,17 @@ pub async fn import_data(
             cipher.created_at.into(),
             cipher.updated_at.into(),
         ])?);
+
+        for collection_id in collection_ids.into_iter().flatten() {
+            stmts.push(
+                db.prepare(collection_query)
+                    .bind(&[cipher.id.clone().into(), collection_id.into()])?,
+            );
+        }
     }
-    
-    if !cipher_stmts.is_empty() {
-        db.batch(cipher_stmts).await.map_err(|_| AppError::Database)?;
+
+    if !stmts.is_empty() {
+        db.batch(stmts).await.map_err(|_| AppError::Database)?;
     }
 
     Ok(Json(()))
@@ -120,3 +454,92 @@ pub async fn import_data(
 fn to_js_val<T: Into<JsValue>>(val: Option<T>) -> JsValue {
     val.map(Into::into).unwrap_or(JsValue::NULL)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_cipher_for_organization_user_is_not_a_member_of() {
+        let org_totp_policies = HashMap::new();
+        let result = check_cipher_organization_membership(&org_totp_policies, 2, "org-1");
+        assert!(matches!(result, Err(AppError::BadRequest(_))));
+    }
+
+    #[test]
+    fn accepts_cipher_for_organization_user_is_a_member_of() {
+        let mut org_totp_policies = HashMap::new();
+        org_totp_policies.insert("org-1".to_string(), false);
+        assert!(check_cipher_organization_membership(&org_totp_policies, 2, "org-1").is_ok());
+    }
+
+    fn accessible_collection(organization_id: &str) -> CollectionAccess {
+        CollectionAccess {
+            organization_id: organization_id.to_string(),
+            has_access: true,
+            read_only: false,
+        }
+    }
+
+    #[test]
+    fn rejects_collection_belonging_to_a_different_organization() {
+        let mut collections = HashMap::new();
+        collections.insert("collection-1".to_string(), accessible_collection("org-2"));
+        let result = check_collection_assignment(&collections, 0, Some("org-1"), "collection-1");
+        assert!(matches!(result, Err(AppError::BadRequest(_))));
+    }
+
+    #[test]
+    fn rejects_collection_that_does_not_exist() {
+        let collections = HashMap::new();
+        let result = check_collection_assignment(&collections, 0, Some("org-1"), "collection-1");
+        assert!(matches!(result, Err(AppError::BadRequest(_))));
+    }
+
+    #[test]
+    fn rejects_collection_on_a_cipher_with_no_organization() {
+        let mut collections = HashMap::new();
+        collections.insert("collection-1".to_string(), accessible_collection("org-1"));
+        let result = check_collection_assignment(&collections, 0, None, "collection-1");
+        assert!(matches!(result, Err(AppError::BadRequest(_))));
+    }
+
+    #[test]
+    fn rejects_collection_the_user_has_no_grant_on() {
+        let mut collections = HashMap::new();
+        collections.insert(
+            "collection-1".to_string(),
+            CollectionAccess {
+                organization_id: "org-1".to_string(),
+                has_access: false,
+                read_only: false,
+            },
+        );
+        let result = check_collection_assignment(&collections, 0, Some("org-1"), "collection-1");
+        assert!(matches!(result, Err(AppError::BadRequest(_))));
+    }
+
+    #[test]
+    fn rejects_collection_the_user_can_only_read() {
+        let mut collections = HashMap::new();
+        collections.insert(
+            "collection-1".to_string(),
+            CollectionAccess {
+                organization_id: "org-1".to_string(),
+                has_access: true,
+                read_only: true,
+            },
+        );
+        let result = check_collection_assignment(&collections, 0, Some("org-1"), "collection-1");
+        assert!(matches!(result, Err(AppError::BadRequest(_))));
+    }
+
+    #[test]
+    fn accepts_collection_belonging_to_the_cipher_s_organization() {
+        let mut collections = HashMap::new();
+        collections.insert("collection-1".to_string(), accessible_collection("org-1"));
+        assert!(
+            check_collection_assignment(&collections, 0, Some("org-1"), "collection-1").is_ok()
+        );
+    }
+}